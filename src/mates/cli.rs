@@ -6,9 +6,117 @@ use std::borrow::ToOwned;
 
 use vobject::{Component,Property,parse_component,write_component};
 use email::rfc5322::Rfc5322Parser;
+use email::MimeMessage;
+use rustc_serialize::base64::FromBase64;
 use uuid::Uuid;
 use atomicwrites::{AtomicFile,AllowOverwrite,DisallowOverwrite};
 
+use tantivy::{Index,Document};
+use tantivy::schema::{Schema,SchemaBuilder,Field,STORED,STRING,TEXT};
+use tantivy::query::QueryParser;
+use tantivy::collector::TopCollector;
+
+use clap::{App,SubCommand,Arg,ArgMatches};
+
+/// Default number of results returned by a single index query (the query
+/// commands override it with `--limit`).
+static QUERY_LIMIT: usize = 50;
+
+/// The set of fields stored in the full-text index.
+///
+/// `email` and `filepath` are kept verbatim (string fields) because we hand
+/// them straight back to mutt/editors, while `name` and the catch-all `text`
+/// field are tokenized so queries are ranked and tolerant.
+struct IndexSchema {
+    schema: Schema,
+    email: Field,
+    name: Field,
+    filepath: Field,
+    text: Field
+}
+
+fn index_schema() -> IndexSchema {
+    let mut builder = SchemaBuilder::default();
+    let email = builder.add_text_field("email", STRING | STORED);
+    let name = builder.add_text_field("name", TEXT | STORED);
+    let filepath = builder.add_text_field("filepath", STRING | STORED);
+    let text = builder.add_text_field("text", TEXT);
+    IndexSchema {
+        schema: builder.build(),
+        email: email,
+        name: name,
+        filepath: filepath,
+        text: text
+    }
+}
+
+/// Map a tantivy error into the `io::IoError` the rest of the module speaks.
+fn tantivy_error<E: ::std::fmt::Display>(e: E) -> io::IoError {
+    io::IoError {
+        kind: io::OtherIoError,
+        desc: "Index error.",
+        detail: Some(format!("{}", e))
+    }
+}
+
+/// Current on-disk index format. v1 was the flat `email\tname\tfilepath`
+/// file; v2 is the tantivy backend. The marker lets us add columns (phone
+/// numbers, UIDs, categories) later without silently corrupting old indexes.
+static INDEX_VERSION: u32 = 2;
+
+/// Name of the version-marker file kept alongside the tantivy segments.
+static VERSION_FILE: &'static str = "mates-version";
+
+/// Write the current version marker into an index directory.
+fn write_index_version(index_dir: &Path) -> io::IoResult<()> {
+    let af = AtomicFile::new(&index_dir.join(VERSION_FILE), AllowOverwrite, None);
+    af.write(|&: f| f.write_str(format!("mates-index v{}\n", INDEX_VERSION).as_slice()))
+}
+
+/// Read the version marker, defaulting to v1 for indexes predating the
+/// marker (i.e. the old flat-file format).
+fn read_index_version(index_dir: &Path) -> u32 {
+    let path = index_dir.join(VERSION_FILE);
+    match io::File::open(&path).read_to_string() {
+        Ok(content) => content.trim()
+            .trim_left_matches("mates-index v")
+            .parse()
+            .unwrap_or(1),
+        Err(_) => 1
+    }
+}
+
+/// Bring an index forward from `from` to `to`.
+///
+/// Structured as a dispatch so new columns can add their own arm over time;
+/// format changes we can't upgrade in place ask the user to rebuild.
+fn migrate(from: u32, to: u32) -> Result<(), String> {
+    if from == to {
+        return Ok(());
+    };
+    match (from, to) {
+        // The flat-file format cannot be converted in place.
+        (1, _) => Err("Index is outdated, run `mates index` to rebuild.".to_string()),
+        _ => Err(format!(
+            "Don't know how to migrate index from v{} to v{}, run `mates index` to rebuild.",
+            from, to))
+    }
+}
+
+/// Fail with the friendly rebuild hint unless the on-disk index is the format
+/// this binary speaks. Shared by every code path that opens an existing index.
+fn ensure_index_current(index_dir: &Path) -> io::IoResult<()> {
+    let version = read_index_version(index_dir);
+    if version != INDEX_VERSION {
+        try!(migrate(version, INDEX_VERSION).map_err(|e| io::IoError {
+            kind: io::OtherIoError,
+            desc: "Outdated index.",
+            detail: Some(e)
+        }));
+    };
+    Ok(())
+}
+
 macro_rules! main_try {
     ($result: expr, $errmsg: expr) => (
         match $result {
@@ -22,52 +130,163 @@ macro_rules! main_try {
     )
 }
 
-struct Configuration {
+/// A single named address book: a vdir plus the index built from it.
+struct Book {
     index_path: Path,
-    vdir_path: Path,
+    vdir_path: Path
+}
+
+struct Configuration {
+    books: HashMap<String, Book>,
+    active: String,
     editor_cmd: String,
-    grep_cmd: String
+    finder_cmd: Option<String>
+}
+
+/// The subset of `Configuration` that may be set declaratively in the TOML
+/// config file. Every key is optional so env-vars and built-in defaults can
+/// fill the gaps (precedence: env-vars > file > defaults).
+///
+/// Top-level `index_path`/`vdir_path` configure the implicit `default` book
+/// (kept for single-book users); additional books live under `[books.<name>]`.
+#[derive(RustcDecodable, Default)]
+struct FileConfig {
+    index_path: Option<String>,
+    vdir_path: Option<String>,
+    editor_cmd: Option<String>,
+    finder_cmd: Option<String>,
+    default_book: Option<String>,
+    books: Option<HashMap<String, FileBook>>
 }
 
+#[derive(RustcDecodable)]
+struct FileBook {
+    index_path: Option<String>,
+    vdir_path: Option<String>
+}
+
+/// Name of the implicit book configured via env-vars / top-level file keys.
+static DEFAULT_BOOK: &'static str = "default";
+
 impl Configuration {
-    fn from_env(env: Vec<(String, String)>) -> Result<Configuration, String> {
+    /// The currently selected book.
+    fn book(&self) -> &Book {
+        &self.books[self.active]
+    }
+    /// Read a TOML config file from disk.
+    ///
+    /// A missing file is not an error: it decodes to an all-`None`
+    /// `FileConfig`, leaving every value to the env/default layers.
+    fn from_file(path: &Path) -> Result<FileConfig, String> {
+        if !path.is_file() {
+            return Ok(FileConfig::default());
+        };
+
+        let content = match io::File::open(path).read_to_string() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Unable to read config file: {}", e))
+        };
+
+        match ::toml::decode_str(content.as_slice()) {
+            Some(x) => Ok(x),
+            None => Err(format!("Unable to parse config file {}.", path.display()))
+        }
+    }
+
+    /// Overlay the process environment on top of a file config and select the
+    /// active book.
+    ///
+    /// `book` is the `--book` argument (if any); when absent the file's
+    /// `default_book` and finally the implicit `default` book are used.
+    fn from_env(file: FileConfig, env: Vec<(String, String)>,
+                book: Option<String>) -> Result<Configuration, String> {
         let mut dict = HashMap::new();
         dict.extend(env.into_iter().filter(|&(_, ref v)| v.len() > 0));
-        Ok(Configuration {
-            index_path: match dict.remove("MATES_INDEX") {
-                Some(x) => Path::new(x),
-                None => match dict.get("HOME") {
-                    Some(home) => {
-                        os::make_absolute(&Path::new(home).join(".mates_index")).unwrap()
-                    },
-                    None => return Err("Unable to determine user's home directory.".to_owned())
-                }
-            },
-            vdir_path: match dict.remove("MATES_DIR") {
+
+        let home_index = || match dict.get("HOME") {
+            Some(home) => Ok(os::make_absolute(&Path::new(home).join(".mates_index")).unwrap()),
+            None => Err("Unable to determine user's home directory.".to_owned())
+        };
+
+        let mut books = HashMap::new();
+
+        // Explicitly declared books from the config file.
+        if let Some(file_books) = file.books {
+            for (name, fb) in file_books.into_iter() {
+                let vdir = match fb.vdir_path {
+                    Some(x) => Path::new(x),
+                    None => return Err(format!("Book '{}' is missing a vdir_path.", name))
+                };
+                // Each book must own its index; falling back to the shared
+                // `home_index()` would silently point two books at one index.
+                let index = match fb.index_path {
+                    Some(x) => Path::new(x),
+                    None => return Err(format!("Book '{}' is missing an index_path.", name))
+                };
+                books.insert(name, Book { index_path: index, vdir_path: vdir });
+            };
+        };
+
+        // The implicit `default` book, configured via env-vars or top-level
+        // file keys. Only materialised if something actually configures it.
+        let default_vdir = dict.remove("MATES_DIR").or(file.vdir_path);
+        if let Some(vdir) = default_vdir {
+            let index = match dict.remove("MATES_INDEX").or(file.index_path) {
                 Some(x) => Path::new(x),
-                None => return Err("MATES_DIR must be set to your vdir path (directory of vcf-files).".to_owned())
-            },
-            editor_cmd: match dict.remove("MATES_EDITOR") {
-                Some(x) => x,
-                None => match dict.remove("EDITOR") {
-                    Some(x) => x,
-                    None => return Err("MATES_EDITOR or EDITOR must be set.".to_owned())
-                }
-            },
-            grep_cmd: match dict.remove("MATES_GREP") {
-                Some(x) => x,
-                None => "grep".to_owned()
-            }
+                None => try!(home_index())
+            };
+            books.insert(DEFAULT_BOOK.to_string(),
+                         Book { index_path: index, vdir_path: Path::new(vdir) });
+        };
+
+        let active = book.or(file.default_book).unwrap_or(DEFAULT_BOOK.to_string());
+        if !books.contains_key(&active) {
+            return Err(format!(
+                "No such address book '{}'. Set MATES_DIR or declare it in the config file.",
+                active));
+        };
+
+        // `EDITOR` is an environment value too, so it outranks the file layer
+        // (precedence: env-vars > file > defaults).
+        let editor_cmd = match dict.remove("MATES_EDITOR").or(dict.remove("EDITOR")).or(file.editor_cmd) {
+            Some(x) => x,
+            None => return Err("MATES_EDITOR or EDITOR must be set.".to_owned())
+        };
+
+        let finder_cmd = dict.remove("MATES_FINDER").or(file.finder_cmd);
+
+        Ok(Configuration {
+            books: books,
+            active: active,
+            editor_cmd: editor_cmd,
+            finder_cmd: finder_cmd
         })
     }
 
-    fn new() -> Result<Configuration, String> {
-        Configuration::from_env(os::env())
+    /// Locate the config file (honoring `$XDG_CONFIG_HOME`) and overlay the
+    /// environment on top of it, selecting `book` as the active address book.
+    fn new(book: Option<String>) -> Result<Configuration, String> {
+        let env = os::env();
+        let lookup = |&: key: &str| env.iter()
+            .find(|&&(ref k, ref v)| k.as_slice() == key && v.len() > 0)
+            .map(|&(_, ref v)| v.clone());
+
+        let config_home = match lookup("XDG_CONFIG_HOME") {
+            Some(x) => Path::new(x),
+            None => match lookup("HOME") {
+                Some(home) => Path::new(home).join(".config"),
+                None => return Err("Unable to determine user's config directory.".to_owned())
+            }
+        };
+
+        let file = try!(Configuration::from_file(
+            &config_home.join("mates").join("config.toml")));
+        Configuration::from_env(file, env, book)
     }
 }
 
 
-fn build_index(outfile: &Path, dir: &Path) -> io::IoResult<()> {
+fn build_index(index_dir: &Path, dir: &Path) -> io::IoResult<()> {
     if !dir.is_dir() {
         return Err(io::IoError {
             kind: io::MismatchedFileTypeForOperation,
@@ -76,38 +295,52 @@ fn build_index(outfile: &Path, dir: &Path) -> io::IoResult<()> {
         });
     };
 
-    let af = AtomicFile::new(outfile, AllowOverwrite, None);
     let entries = try!(io::fs::readdir(dir));
     let mut errors = false;
 
-    try!(af.write(|&mut: outf| {
-        for entry in entries.iter() {
-            if !entry.is_file() || !entry.filename_str().unwrap_or("").ends_with(".vcf") {
-                continue;
-            }
+    let schema = index_schema();
+    // `Index::create` refuses an already-initialized directory, so a rebuild
+    // must start from an empty one. Clear whatever is there first: a previous
+    // tantivy directory, or the old v1 flat *file* left by an upgrade.
+    if index_dir.is_dir() {
+        try!(io::fs::rmdir_recursive(index_dir));
+    } else if index_dir.exists() {
+        try!(io::fs::unlink(index_dir));
+    };
+    try!(io::fs::mkdir_recursive(index_dir, io::USER_RWX));
+    let index = try!(Index::create(index_dir, schema.schema.clone()).map_err(tantivy_error));
+    let mut writer = try!(index.writer(50_000_000).map_err(tantivy_error));
 
-            let contact = match Contact::from_file(entry.clone()) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Error while reading {}: {}", entry.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
+    for entry in entries.iter() {
+        if !entry.is_file() || !entry.filename_str().unwrap_or("").ends_with(".vcf") {
+            continue;
+        }
 
-            match index_item_from_contact(&contact) {
-                Ok(index_string) => {
-                    try!(outf.write_str(index_string.as_slice()));
-                },
-                Err(e) => {
-                    println!("Error while indexing {}: {}", entry.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
+        let contact = match Contact::from_file(entry.clone()) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Error while reading {}: {}", entry.display(), e);
+                errors = true;
+                continue
+            }
         };
-        Ok(())
-    }));
+
+        match index_document_from_contact(&schema, &contact) {
+            Ok(docs) => {
+                for doc in docs.into_iter() {
+                    writer.add_document(doc);
+                };
+            },
+            Err(e) => {
+                println!("Error while indexing {}: {}", entry.display(), e);
+                errors = true;
+                continue
+            }
+        };
+    };
+
+    try!(writer.commit().map_err(tantivy_error));
+    try!(write_index_version(index_dir));
 
     if errors {
         Err(io::IoError {
@@ -121,7 +354,12 @@ fn build_index(outfile: &Path, dir: &Path) -> io::IoResult<()> {
 }
 
 
-fn index_item_from_contact(contact: &Contact) -> io::IoResult<String> {
+/// Build one index document per EMAIL property of a contact.
+///
+/// The `text` field is a catch-all of the tokenized vCard bits we want to
+/// match against (name, email, org, nickname); `email`/`name`/`filepath` are
+/// stored so `index_query` can reconstruct an `IndexItem` from a hit.
+fn index_document_from_contact(schema: &IndexSchema, contact: &Contact) -> io::IoResult<Vec<Document>> {
     let name = match contact.component.single_prop("FN") {
         Some(name) => name.value_as_string(),
         None => return Err(io::IoError {
@@ -131,54 +369,134 @@ fn index_item_from_contact(contact: &Contact) -> io::IoResult<String> {
         })
     };
 
+    let mut text = name.clone();
+    for prop in ["ORG", "NICKNAME"].iter() {
+        if let Some(p) = contact.component.single_prop(*prop) {
+            text.push(' ');
+            text.push_str(p.value_as_string().as_slice());
+        };
+    };
+
+    let filepath = format!("{}", contact.path.display());
     let emails = contact.component.all_props("EMAIL");
-    let mut rv = String::new();
+    let mut rv = Vec::new();
     for email in emails.iter() {
-        rv.push_str(format!("{}\t{}\t{}\n", email.value_as_string(), name, contact.path.display()).as_slice());
+        let email = email.value_as_string();
+        let mut doc = Document::default();
+        doc.add_text(schema.email, email.as_slice());
+        doc.add_text(schema.name, name.as_slice());
+        doc.add_text(schema.filepath, filepath.as_slice());
+        doc.add_text(schema.text, format!("{} {}", text, email).as_slice());
+        rv.push(doc);
     };
     Ok(rv)
 }
 
 
-pub fn cli_main() {
-    let mut args = os::args().into_iter();
-    let program = args.next().unwrap_or("mates".to_string());
-
-    let help = format!("Usage: {} COMMAND
-Commands:
-    index:
-        Rewrite/create the index.
-    mutt-query <query>:
-        Search for contact, output is usable for mutt's query_command.
-    file-query <query>:
-        Search for contact, return just the filename.
-    email-query <query>:
-        Search for contact, return \"name <email>\".
-    add:
-        Take mail from stdin, add sender to contacts. Print filename.
-    edit <file-or-query>:
-        Open contact (given by filepath or search-string) in $MATES_EDITOR. If
-        the file is cleared, the contact is removed.", program);
-
-    let print_help = |&:| {
-        println!("{}", help);
-    };
-
-    let command = match args.next() {
-        Some(x) => x,
-        None => {
-            print_help();
-            os::set_exit_status(1);
-            return;
-        }
+/// Incrementally add a single freshly-created contact to the index.
+///
+/// Unlike `build_index` this opens the existing index rather than rebuilding
+/// it, so `add` stays cheap on large vdirs. A brand-new user who has never run
+/// `mates index` has no index at all; rather than nagging them to rebuild an
+/// index that doesn't exist, we create an empty one on the fly — matching the
+/// lazy append-on-create behaviour of earlier versions.
+fn add_to_index(index_dir: &Path, contact: &Contact) -> io::IoResult<()> {
+    let schema = index_schema();
+    let index = if index_dir.exists() {
+        // An existing directory (or the old v1 flat file) is version-checked;
+        // a stale format still yields the "run `mates index`" message.
+        try!(ensure_index_current(index_dir));
+        try!(Index::open(index_dir).map_err(tantivy_error))
+    } else {
+        try!(io::fs::mkdir_recursive(index_dir, io::USER_RWX));
+        let index = try!(Index::create(index_dir, schema.schema.clone()).map_err(tantivy_error));
+        try!(write_index_version(index_dir));
+        index
+    };
+    let mut writer = try!(index.writer(50_000_000).map_err(tantivy_error));
+    for doc in try!(index_document_from_contact(&schema, contact)).into_iter() {
+        writer.add_document(doc);
     };
+    try!(writer.commit().map_err(tantivy_error));
+    Ok(())
+}
+
+
+/// Build the positional `query` argument and the `--limit`/`--format` flags
+/// shared by the query subcommands.
+fn query_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("query")
+             .help("Search string.")
+             .required(true))
+        .arg(Arg::with_name("limit")
+             .long("limit").short("n").takes_value(true)
+             .validator(validate_limit)
+             .help("Maximum number of results to return."))
+        .arg(Arg::with_name("format")
+             .long("format").takes_value(true)
+             .possible_values(&["plain", "mutt", "json"])
+             .help("Output format."))
+}
 
-    if command == "--help" || command == "help" || command == "-h" {
-        print_help();
-        return;
+/// Reject a non-numeric `--limit` at parse time so clap exits with a usage
+/// error (and a non-zero status) instead of silently ignoring the value.
+fn validate_limit(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a valid result limit.", value))
     }
+}
+
+/// Parse the already-validated `--limit` value, falling back to the default
+/// result count when the flag is absent.
+fn arg_limit(matches: &ArgMatches) -> usize {
+    matches.value_of("limit")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(QUERY_LIMIT)
+}
 
-    let config = match Configuration::new() {
+/// Resolve the `--format` value, falling back to the command's native format.
+fn arg_format(matches: &ArgMatches, default: Format) -> Format {
+    match matches.value_of("format") {
+        Some("plain") => Format::Plain,
+        Some("mutt") => Format::Mutt,
+        Some("json") => Format::Json,
+        _ => default
+    }
+}
+
+pub fn cli_main() {
+    let matches = App::new("mates")
+        .about("A simple commandline addressbook over a vdir.")
+        .arg(Arg::with_name("book")
+             .long("book").takes_value(true).global(true)
+             .help("The address book to operate on."))
+        .subcommand(SubCommand::with_name("index")
+                    .about("Rewrite/create the index.")
+                    .arg(Arg::with_name("all")
+                         .long("all")
+                         .help("Rebuild the index of every configured book.")))
+        .subcommand(query_subcommand("mutt-query",
+                    "Search for contact, output is usable for mutt's query_command."))
+        .subcommand(query_subcommand("file-query",
+                    "Search for contact, return just the filename."))
+        .subcommand(query_subcommand("email-query",
+                    "Search for contact, return \"name <email>\"."))
+        .subcommand(SubCommand::with_name("add")
+                    .about("Take mail from stdin, add its attached vCard (or else its sender) \
+                            to contacts. Print filename."))
+        .subcommand(SubCommand::with_name("edit")
+                    .about("Open contact (given by filepath or search-string) in \
+                            $MATES_EDITOR. If the file is cleared, the contact is removed.")
+                    .arg(Arg::with_name("query")
+                         .help("Filepath or search string.")
+                         .required(true)))
+        .get_matches();
+
+    let book = matches.value_of("book").map(|x| x.to_string());
+    let config = match Configuration::new(book) {
         Ok(x) => x,
         Err(e) => {
             println!("Error while reading configuration: {}", e);
@@ -187,44 +505,44 @@ Commands:
         }
     };
 
-    match command.as_slice() {
-        "index" => {
-            println!("Rebuilding index file \"{}\"...", config.index_path.display());
-            main_try!(build_index(&config.index_path, &config.vdir_path), "Failed to build index");
+    match matches.subcommand() {
+        ("index", Some(sub)) => {
+            let books: Vec<&Book> = if sub.is_present("all") {
+                config.books.values().collect()
+            } else {
+                vec![config.book()]
+            };
+            for b in books.into_iter() {
+                println!("Rebuilding index \"{}\"...", b.index_path.display());
+                main_try!(build_index(&b.index_path, &b.vdir_path), "Failed to build index");
+            };
         },
-        "mutt-query" => {
-            let query = args.next().unwrap_or("".to_string());
-            main_try!(mutt_query(&config, query.as_slice()), "Failed to execute grep");
+        ("mutt-query", Some(sub)) => {
+            let query = sub.value_of("query").unwrap_or("");
+            main_try!(mutt_query(&config, query, arg_limit(sub), arg_format(sub, Format::Mutt)),
+                      "Failed to query index");
         },
-        "file-query" => {
-            let query = args.next().unwrap_or("".to_string());
-            main_try!(file_query(&config, query.as_slice()), "Failed to execute grep");
+        ("file-query", Some(sub)) => {
+            let query = sub.value_of("query").unwrap_or("");
+            main_try!(file_query(&config, query, arg_limit(sub), arg_format(sub, Format::Plain)),
+                      "Failed to query index");
         },
-        "email-query" => {
-            let query = args.next().unwrap_or("".to_string());
-            main_try!(email_query(&config, query.as_slice()), "Failed to execute grep");
+        ("email-query", Some(sub)) => {
+            let query = sub.value_of("query").unwrap_or("");
+            main_try!(email_query(&config, query, arg_limit(sub), arg_format(sub, Format::Plain)),
+                      "Failed to query index");
         },
-        "add" => {
-            let contact = main_try!(add_contact(&config.vdir_path), "Failed to add contact");
+        ("add", Some(_)) => {
+            let contact = main_try!(add_contact(&config.book().vdir_path), "Failed to add contact");
             println!("{}", contact.path.display());
-
-            let mut index_fp = main_try!(io::File::open_mode(
-                &config.index_path,
-                io::Append,
-                io::Write),
-                "Failed to open index"
-            );
-
-            let index_entry = main_try!(index_item_from_contact(&contact), "Failed to generate index");
-            main_try!(index_fp.write_str(index_entry.as_slice()), "Failed to write to index");
+            main_try!(add_to_index(&config.book().index_path, &contact), "Failed to update index");
         },
-        "edit" => {
-            let query = args.next().unwrap_or("".to_string());
-            main_try!(edit_contact(&config, query.as_slice()), "Failed to edit contact");
+        ("edit", Some(sub)) => {
+            let query = sub.value_of("query").unwrap_or("");
+            main_try!(edit_contact(&config, query), "Failed to edit contact");
         },
         _ => {
-            println!("Invalid command: {}", command);
-            print_help();
+            println!("{}", matches.usage());
             os::set_exit_status(1);
         }
     };
@@ -232,20 +550,119 @@ Commands:
 
 fn add_contact(contact_dir: &Path) -> io::IoResult<Contact> {
     let stdin = try!(io::stdin().lock().read_to_string());
-    let from_header = match read_sender_from_email(stdin.as_slice()) {
-        Some(x) => x,
-        None => return Err(io::IoError {
-            kind: io::InvalidInput,
-            desc: "Couldn't find From-header in email.",
-            detail: None
-        })
+
+    // A full vCard attached to the message is richer than anything we can
+    // synthesize from the From-header, so prefer it when present.
+    let contact = match vcard_from_email(stdin.as_slice()) {
+        Some(component) => Contact::from_component(component, contact_dir),
+        None => {
+            let from_header = match read_sender_from_email(stdin.as_slice()) {
+                Some(x) => x,
+                None => return Err(io::IoError {
+                    kind: io::InvalidInput,
+                    desc: "Couldn't find From-header in email.",
+                    detail: None
+                })
+            };
+            let (fullname, email) = parse_from_header(&from_header);
+            let fullname = fullname.map(decode_rfc2047);
+            Contact::generate(fullname.as_ref().map(|x| x.as_slice()), email, contact_dir)
+        }
     };
-    let (fullname, email) = parse_from_header(&from_header);
-    let contact = Contact::generate(fullname, email, contact_dir);
+
     try!(contact.write_create());
     Ok(contact)
 }
 
+/// Walk a MIME message and return the first vCard body found, if any.
+///
+/// A part qualifies when its content-type is `text/vcard`/`text/x-vcard` or
+/// its `Content-Disposition` mentions `.vcf`; its body, once its transfer
+/// encoding is undone, goes to the vObject parser so TEL/ORG/ADR and friends
+/// are preserved.
+fn vcard_from_email(raw: &str) -> Option<Component> {
+    let message = match MimeMessage::parse(raw) {
+        Ok(x) => x,
+        Err(_) => return None
+    };
+    find_vcard(&message)
+}
+
+fn find_vcard(message: &MimeMessage) -> Option<Component> {
+    let is_vcard = {
+        let content_type = message.headers.get_value::<String>("Content-Type".to_string());
+        let disposition = message.headers.get_value::<String>("Content-Disposition".to_string());
+        let matches = |&: v: &Option<String>, needle: &str|
+            v.as_ref().map_or(false, |s| s.as_slice().to_ascii_lowercase().contains(needle));
+
+        matches(&content_type, "text/vcard") || matches(&content_type, "text/x-vcard") ||
+            matches(&disposition, ".vcf")
+    };
+
+    if is_vcard {
+        let body = decode_transfer_encoding(message);
+        if let Ok(component) = parse_component(body.as_slice()) {
+            return Some(component);
+        };
+    };
+
+    for child in message.children.iter() {
+        if let Some(component) = find_vcard(child) {
+            return Some(component);
+        };
+    };
+    None
+}
+
+
+/// Decode a MIME part's body according to its `Content-Transfer-Encoding`, so
+/// a `base64`/`quoted-printable` `.vcf` attachment reaches the vObject parser
+/// as the raw vCard text rather than its still-encoded form. The identity
+/// encodings (`7bit`/`8bit`/`binary`) and anything unrecognised — or a body
+/// that fails to decode — fall through untouched.
+fn decode_transfer_encoding(message: &MimeMessage) -> String {
+    let encoding = message.headers
+        .get_value::<String>("Content-Transfer-Encoding".to_string())
+        .map(|s| s.trim().to_ascii_lowercase());
+
+    match encoding.as_ref().map(|s| s.as_slice()) {
+        Some("base64") => message.body.as_slice().from_base64().ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_else(|| message.body.clone()),
+        Some("quoted-printable") => String::from_utf8(
+                decode_mime_quoted_printable(message.body.as_slice()))
+            .unwrap_or_else(|_| message.body.clone()),
+        _ => message.body.clone()
+    }
+}
+
+/// Decode a quoted-printable body (RFC 2045): `=XX` is a hex byte and a `=`
+/// immediately before a line break is a soft break that is dropped. Unlike the
+/// RFC 2047 "Q" variant, `_` is a literal underscore here.
+fn decode_mime_quoted_printable(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut rv = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'=' && i + 1 < bytes.len() && (bytes[i + 1] == b'\r' || bytes[i + 1] == b'\n') {
+            // Soft line break: swallow the '=' and the following CR?LF.
+            i += 1;
+            if bytes[i] == b'\r' && i + 1 < bytes.len() && bytes[i + 1] == b'\n' {
+                i += 1;
+            };
+        } else if bytes[i] == b'=' && i + 2 < bytes.len() {
+            let hex = input.slice(i + 1, i + 3);
+            match u8::from_str_radix(hex, 16) {
+                Ok(b) => { rv.push(b); i += 2; },
+                Err(_) => rv.push(b'=')
+            };
+        } else {
+            rv.push(bytes[i]);
+        };
+        i += 1;
+    };
+    rv
+}
 
 /// Return a tuple (fullname, email)
 fn parse_from_header<'a>(s: &'a String) -> (Option<&'a str>, Option<&'a str>) {
@@ -274,33 +691,81 @@ fn read_sender_from_email(email: &str) -> Option<String> {
     None
 }
 
-fn edit_contact(config: &Configuration, query: &str) -> Result<(), String> {
+/// Decode any RFC 2047 encoded-words in a header value so UTF-8 display-names
+/// aren't stored mangled. Tokens that aren't encoded-words are passed through
+/// untouched.
+fn decode_rfc2047(input: &str) -> String {
+    input.split(' ')
+        .map(|token| decode_encoded_word(token).unwrap_or_else(|| token.to_string()))
+        .collect::<Vec<_>>()
+        .connect(" ")
+}
 
-    let results = {
-        if config.vdir_path.join(query).is_file() {
-            vec![query.to_string()]
-        } else {
-            let results_iter = match index_query(config, query) {
-                Ok(x) => x,
-                Err(e) => return Err(format!("Error while fetching index: {}", e))
-            };
+/// Decode a single `=?charset?enc?text?=` encoded-word, or `None` if the token
+/// isn't one. Only `B` (base64) and `Q` (quoted-printable) encodings are
+/// understood; the charset is assumed to be UTF-8 compatible.
+fn decode_encoded_word(token: &str) -> Option<String> {
+    if !token.starts_with("=?") || !token.ends_with("?=") || token.len() < 4 {
+        return None;
+    };
 
-            results_iter.filter_map(|x| {
-                if x.filepath.len() > 0 {
-                    Some(x.filepath)
-                } else {
-                    None
-                }}).collect()
-        }
+    let inner = token.slice(2, token.len() - 2);
+    let fields: Vec<&str> = inner.split('?').collect();
+    if fields.len() != 3 {
+        return None;
     };
 
-    if results.len() < 1 {
-        return Err("No such contact.".to_string());
-    } else if results.len() > 1 {
-        return Err("Ambiguous query.".to_string());
-    }
+    let bytes = match fields[1].to_ascii_uppercase().as_slice() {
+        "B" => match fields[2].from_base64() {
+            Ok(x) => x,
+            Err(_) => return None
+        },
+        "Q" => decode_quoted_printable(fields[2]),
+        _ => return None
+    };
 
-    let fpath = results[0].as_slice();
+    String::from_utf8(bytes).ok()
+}
+
+/// Decode the "Q" variant of RFC 2047: `_` means space and `=XX` is a
+/// hex-encoded byte.
+fn decode_quoted_printable(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut rv = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => rv.push(b' '),
+            b'=' if i + 2 < bytes.len() => {
+                let hex = input.slice(i + 1, i + 3);
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => { rv.push(b); i += 2; },
+                    Err(_) => rv.push(b'=')
+                };
+            },
+            b => rv.push(b)
+        };
+        i += 1;
+    };
+    rv
+}
+
+fn edit_contact(config: &Configuration, query: &str) -> Result<(), String> {
+
+    let fpath = if config.book().vdir_path.join(query).is_file() {
+        query.to_string()
+    } else {
+        let results = match index_query(config, query, QUERY_LIMIT) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Error while fetching index: {}", e))
+        };
+        let results: Vec<IndexItem> = results.into_iter()
+            .filter(|x| x.filepath.len() > 0)
+            .collect();
+        try!(select_contact(config, results))
+    };
+
+    let fpath = fpath.as_slice();
     let mut process = match io::Command::new("sh")
         .arg("-c")
         // clear stdin, http://unix.stackexchange.com/a/77593
@@ -331,98 +796,227 @@ fn edit_contact(config: &Configuration, query: &str) -> Result<(), String> {
     Ok(())
 }
 
-fn mutt_query<'a>(config: &Configuration, query: &str) -> io::IoResult<()> {
-    println!("");  // For some reason mutt requires an empty line
-    for item in try!(index_query(config, query)) {
-        if item.email.len() > 0 && item.name.len() > 0 {
-            println!("{}\t{}\t{}", item.email, item.name, item.filepath);
-        };
+/// Pick a single contact out of a result set.
+///
+/// Zero or one match behaves as before. On ambiguity we only prompt when
+/// stdout is a TTY (so piped/scripted use stays deterministic and keeps
+/// erroring); otherwise the configured `finder_cmd` or a minimal built-in
+/// prompt chooses between the candidates.
+fn select_contact(config: &Configuration, items: Vec<IndexItem>) -> Result<String, String> {
+    if items.len() < 1 {
+        return Err("No such contact.".to_string());
+    } else if items.len() == 1 {
+        return Ok(items[0].filepath.clone());
+    } else if !stdout_is_tty() {
+        return Err("Ambiguous query.".to_string());
+    }
+
+    let lines: Vec<String> = items.iter()
+        .map(|i| format!("{}\t{}\t{}", i.name, i.email, i.filepath))
+        .collect();
+
+    let chosen = match config.finder_cmd {
+        Some(ref cmd) => try!(run_finder(cmd.as_slice(), &lines)),
+        None => try!(builtin_picker(&lines))
     };
-    Ok(())
+
+    match items.into_iter().find(|i|
+            format!("{}\t{}\t{}", i.name, i.email, i.filepath) == chosen) {
+        Some(i) => Ok(i.filepath),
+        None => Err("Selection did not match any contact.".to_string())
+    }
 }
 
-fn file_query<'a>(config: &Configuration, query: &str) -> io::IoResult<()> {
-    for item in try!(index_query(config, query)) {
-        if item.filepath.len() > 0 {
-            println!("{}", item.filepath)
+/// Feed the candidate lines to an external finder (e.g. fzf) on stdin and read
+/// the selected line back from its stdout.
+fn run_finder(cmd: &str, lines: &Vec<String>) -> Result<String, String> {
+    let mut process = match io::Command::new("sh")
+        .arg("-c").arg(cmd)
+        .stdin(io::process::CreatePipe(true, false))
+        .stdout(io::process::CreatePipe(false, true))
+        .stderr(io::process::InheritFd(2))
+        .spawn() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Error while invoking finder: {}", e))
         };
-    };
-    Ok(())
-}
 
-fn email_query<'a>(config: &Configuration, query: &str) -> io::IoResult<()> {
-    for item in try!(index_query(config, query)) {
-        if item.name.len() > 0 && item.email.len() > 0 {
-            println!("{} <{}>", item.name, item.email);
+    if let Some(mut stdin) = process.stdin.take() {
+        if let Err(e) = stdin.write_str(lines.connect("\n").as_slice()) {
+            return Err(format!("Error while feeding finder: {}", e));
         };
     };
-    Ok(())
-}
 
-fn index_query<'a>(config: &Configuration, query: &str) -> io::IoResult<IndexIterator<'a>> {
-    let mut process = try!(io::Command::new(config.grep_cmd.as_slice())
-        .arg(query.as_slice())
-        .stderr(io::process::InheritFd(2))
-        .spawn());
+    let output = match process.wait_with_output() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error while invoking finder: {}", e))
+    };
 
-    {
-        let mut index_fp = try!(io::File::open(&config.index_path));
-        let mut stdin = process.stdin.take().unwrap();
-        try!(stdin.write_str(try!(index_fp.read_to_string()).as_slice()));
+    match String::from_utf8(output.output) {
+        Ok(s) => Ok(s.trim().to_string()),
+        Err(_) => Err("Finder returned invalid UTF-8.".to_string())
     }
+}
 
-    let stream = match process.stdout.as_mut() {
-        Some(x) => x,
-        None => return Err(io::IoError {
-            kind: io::IoUnavailable,
-            desc: "Failed to get stdout from grep process.",
-            detail: None
-        })
+/// Minimal numbered prompt used when no `finder_cmd` is configured.
+fn builtin_picker(lines: &Vec<String>) -> Result<String, String> {
+    for (i, line) in lines.iter().enumerate() {
+        println!("{}: {}", i + 1, line);
+    };
+    print!("Selection: ");
+    // stdout is line-buffered; flush so the prompt shows before we block on input.
+    if let Err(e) = io::stdio::stdout().flush() {
+        return Err(format!("Error while writing prompt: {}", e));
+    };
+
+    let input = match io::stdin().lock().read_line() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error while reading selection: {}", e))
     };
 
-    let output = try!(stream.read_to_string());
-    Ok(IndexIterator::new(&output))
+    match input.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= lines.len() => Ok(lines[n - 1].clone()),
+        _ => Err("Invalid selection.".to_string())
+    }
 }
 
-struct IndexItem<'a> {
-    pub email: String,
-    pub name: String,
-    pub filepath: String
+/// Whether standard output is connected to a terminal.
+fn stdout_is_tty() -> bool {
+    io::stdio::stdout_raw().isatty()
 }
 
-impl<'a> IndexItem<'a> {
-    fn new(line: String) -> IndexItem<'a> {
-        let mut parts = line.split('\t');
+/// The output format selected via `--format`.
+#[derive(Copy)]
+enum Format {
+    Plain,
+    Mutt,
+    Json
+}
 
-        IndexItem {
-            email: parts.next().unwrap_or("").to_string(),
-            name: parts.next().unwrap_or("").to_string(),
-            filepath: parts.next().unwrap_or("").to_string()
+/// A single query result as serialized in `--format json`.
+#[derive(RustcEncodable)]
+struct JsonItem {
+    email: String,
+    name: String,
+    filepath: String
+}
+
+/// Render a set of results in the requested format.
+///
+/// `Mutt` and `Json` are rendered the same way for every command; the `plain`
+/// callback supplies each command's native one-line rendering.
+fn emit_results<F>(items: Vec<IndexItem>, format: Format, plain: F) -> io::IoResult<()>
+        where F: Fn(&IndexItem) -> Option<String> {
+    match format {
+        Format::Json => {
+            let encodable: Vec<JsonItem> = items.iter().map(|i| JsonItem {
+                email: i.email.clone(),
+                name: i.name.clone(),
+                filepath: i.filepath.clone()
+            }).collect();
+            match ::rustc_serialize::json::encode(&encodable) {
+                Ok(s) => println!("{}", s),
+                Err(e) => return Err(io::IoError {
+                    kind: io::OtherIoError,
+                    desc: "Failed to encode results as JSON.",
+                    detail: Some(format!("{}", e))
+                })
+            };
+        },
+        Format::Mutt => {
+            println!("");  // For some reason mutt requires an empty line
+            for item in items.iter() {
+                if item.email.len() > 0 && item.name.len() > 0 {
+                    println!("{}\t{}\t{}", item.email, item.name, item.filepath);
+                };
+            };
+        },
+        Format::Plain => {
+            for item in items.iter() {
+                if let Some(line) = plain(item) {
+                    println!("{}", line);
+                };
+            };
         }
-    }
+    };
+    Ok(())
 }
 
-struct IndexIterator<'a> {
-    linebuffer: Vec<String>
+fn mutt_query<'a>(config: &Configuration, query: &str, limit: usize, format: Format) -> io::IoResult<()> {
+    let items = try!(index_query(config, query, limit));
+    emit_results(items, format, |item| {
+        if item.email.len() > 0 && item.name.len() > 0 {
+            Some(format!("{}\t{}\t{}", item.email, item.name, item.filepath))
+        } else {
+            None
+        }
+    })
 }
 
-impl<'a> IndexIterator<'a> {
-    fn new(output: &String) -> IndexIterator<'a> {
+fn file_query<'a>(config: &Configuration, query: &str, limit: usize, format: Format) -> io::IoResult<()> {
+    let items = try!(index_query(config, query, limit));
+    emit_results(items, format, |item| {
+        if item.filepath.len() > 0 {
+            Some(item.filepath.clone())
+        } else {
+            None
+        }
+    })
+}
 
-        let rv = output.split('\n').map(|x: &str| x.to_string()).collect();
-        IndexIterator {
-            linebuffer: rv
+fn email_query<'a>(config: &Configuration, query: &str, limit: usize, format: Format) -> io::IoResult<()> {
+    let items = try!(index_query(config, query, limit));
+    emit_results(items, format, |item| {
+        if item.name.len() > 0 && item.email.len() > 0 {
+            Some(format!("{} <{}>", item.name, item.email))
+        } else {
+            None
         }
-    }
+    })
+}
+
+fn index_query(config: &Configuration, query: &str, limit: usize) -> io::IoResult<Vec<IndexItem>> {
+    let index_dir = &config.book().index_path;
+    try!(ensure_index_current(index_dir));
+
+    let schema = index_schema();
+    let index = try!(Index::open(index_dir).map_err(tantivy_error));
+    try!(index.load_searchers().map_err(tantivy_error));
+    let searcher = index.searcher();
+
+    let parser = QueryParser::for_index(&index, vec![schema.name, schema.text]);
+    let parsed = try!(parser.parse_query(query).map_err(|e| tantivy_error(format!("{:?}", e))));
+
+    let mut collector = TopCollector::with_limit(limit);
+    try!(searcher.search(&*parsed, &mut collector).map_err(tantivy_error));
+
+    let mut rv = Vec::new();
+    for address in collector.docs().into_iter() {
+        let doc = try!(searcher.doc(&address).map_err(tantivy_error));
+        rv.push(IndexItem::from_doc(&schema, &doc));
+    };
+    Ok(rv)
 }
 
-impl<'a> Iterator for IndexIterator<'a> {
-    type Item = IndexItem<'a>;
+struct IndexItem {
+    pub email: String,
+    pub name: String,
+    pub filepath: String
+}
 
-    fn next(&mut self) -> Option<IndexItem<'a>> {
-        match self.linebuffer.pop() {
-            Some(x) => Some(IndexItem::new(x)),
-            None => None
+impl IndexItem {
+    /// Reconstruct an item from a hit's stored fields.
+    fn from_doc(schema: &IndexSchema, doc: &Document) -> IndexItem {
+        let first_text = |&: field: Field| -> String {
+            match doc.get_first(field).and_then(|v| v.text()) {
+                Some(x) => x.to_string(),
+                None => String::new()
+            }
+        };
+
+        IndexItem {
+            email: first_text(schema.email),
+            name: first_text(schema.name),
+            filepath: first_text(schema.filepath)
         }
     }
 }
@@ -447,22 +1041,36 @@ impl Contact {
         Ok(Contact { component: item, path: path })
     }
 
-    pub fn generate(fullname: Option<&str>, email: Option<&str>, dir: &Path) -> Contact {
-        let (uid, contact_path) = {
-            let mut uid;
-            let mut contact_path;
-            loop {
-                uid = Uuid::new_v4().to_simple_string();
-                contact_path = dir.join(Path::new(format!("{}.vcf", uid)));
-                if !contact_path.exists() {
-                    break
-                }
-            };
-            (uid, contact_path)
+    /// Allocate a fresh unused `<uid>.vcf` path in `dir`.
+    fn allocate_path(dir: &Path) -> (String, Path) {
+        let mut uid;
+        let mut contact_path;
+        loop {
+            uid = Uuid::new_v4().to_simple_string();
+            contact_path = dir.join(Path::new(format!("{}.vcf", uid)));
+            if !contact_path.exists() {
+                break
+            }
         };
+        (uid, contact_path)
+    }
+
+    pub fn generate(fullname: Option<&str>, email: Option<&str>, dir: &Path) -> Contact {
+        let (uid, contact_path) = Contact::allocate_path(dir);
         Contact { path: contact_path, component: generate_component(uid, fullname, email) }
     }
 
+    /// Store an already-parsed vCard component (e.g. one extracted from a
+    /// message attachment), giving it a fresh filename and a UID if it lacks
+    /// one.
+    pub fn from_component(mut component: Component, dir: &Path) -> Contact {
+        let (uid, contact_path) = Contact::allocate_path(dir);
+        if component.single_prop("UID").is_none() {
+            component.all_props_mut("UID").push(Property::new(uid.as_slice()));
+        };
+        Contact { path: contact_path, component: component }
+    }
+
     pub fn write_create(&self) -> io::IoResult<()> {
         let string = write_component(&self.component);
         let af = AtomicFile::new(&self.path, DisallowOverwrite, None);
@@ -489,3 +1097,84 @@ fn generate_component(uid: String, fullname: Option<&str>, email: Option<&str>)
     comp.all_props_mut("UID").push(Property::new(uid.as_slice()));
     comp
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, parse_from_header, decode_encoded_word,
+                decode_quoted_printable, decode_mime_quoted_printable};
+
+    #[test]
+    fn migrate_is_a_noop_within_one_version() {
+        assert!(migrate(2, 2).is_ok());
+    }
+
+    #[test]
+    fn migrate_from_flat_file_asks_for_rebuild() {
+        let err = migrate(1, 2).unwrap_err();
+        assert!(err.contains("mates index"));
+    }
+
+    #[test]
+    fn migrate_unknown_gap_asks_for_rebuild() {
+        let err = migrate(2, 3).unwrap_err();
+        assert!(err.contains("v2 to v3"));
+        assert!(err.contains("mates index"));
+    }
+
+    #[test]
+    fn parse_from_header_splits_name_and_angle_addr() {
+        let header = "Jane Doe <jane@example.com>".to_string();
+        let (name, email) = parse_from_header(&header);
+        assert_eq!(name, Some("Jane Doe"));
+        assert_eq!(email, Some("jane@example.com"));
+    }
+
+    #[test]
+    fn parse_from_header_bare_address_has_no_name() {
+        let header = "jane@example.com".to_string();
+        let (name, email) = parse_from_header(&header);
+        assert_eq!(name, None);
+        assert_eq!(email, Some("jane@example.com"));
+    }
+
+    #[test]
+    fn decode_encoded_word_base64() {
+        assert_eq!(decode_encoded_word("=?utf-8?B?SsO2cmc=?="),
+                   Some("Jörg".to_string()));
+    }
+
+    #[test]
+    fn decode_encoded_word_quoted_printable() {
+        assert_eq!(decode_encoded_word("=?utf-8?Q?J=C3=B6rg_Doe?="),
+                   Some("Jörg Doe".to_string()));
+    }
+
+    #[test]
+    fn decode_encoded_word_rejects_plain_token() {
+        assert_eq!(decode_encoded_word("plain"), None);
+        assert_eq!(decode_encoded_word("=?utf-8?X?nope?="), None);
+    }
+
+    #[test]
+    fn decode_quoted_printable_underscore_is_space() {
+        assert_eq!(decode_quoted_printable("a_b"), b"a b".to_vec());
+    }
+
+    #[test]
+    fn decode_quoted_printable_truncated_escape_is_literal() {
+        // A `=` with fewer than two trailing hex digits stays verbatim.
+        assert_eq!(decode_quoted_printable("a=4"), b"a=4".to_vec());
+    }
+
+    #[test]
+    fn decode_mime_quoted_printable_hex_and_soft_break() {
+        assert_eq!(decode_mime_quoted_printable("TEL:=2B1=\r\n555"),
+                   b"TEL:+1555".to_vec());
+    }
+
+    #[test]
+    fn decode_mime_quoted_printable_keeps_underscore() {
+        assert_eq!(decode_mime_quoted_printable("a_b"), b"a_b".to_vec());
+    }
+}